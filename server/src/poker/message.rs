@@ -0,0 +1,28 @@
+//! Messages a [`Session`](crate::websocket::session::Session) sends to its
+//! [`AppRoom`](crate::AppRoom) to keep the room's presence roster up to date.
+//!
+//! The room tracks the connected [`ClientId`]s and, on every join and leave,
+//! broadcasts a `ResponseMessage::Presence { room_uuid, online, count }` to all
+//! members so the scrum-poker UI gets a real-time roster without polling.
+
+use actix::prelude::*;
+
+use crate::client::ClientId;
+use crate::user::info::SharedUserInfo;
+
+/// A client has joined (or rejoined) the room; add it to the roster and
+/// broadcast the updated presence to every member.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PresenceJoinMessage {
+    pub client_id: ClientId,
+    pub user_info: SharedUserInfo,
+}
+
+/// A client has left the room (explicit leave or dropped connection); remove it
+/// from the roster and broadcast the updated presence to every member.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PresenceLeaveMessage {
+    pub client_id: ClientId,
+}
@@ -0,0 +1,462 @@
+//! Cross-node room federation.
+//!
+//! A room lives on exactly one node, but a `JoinRoom` may arrive on any node in
+//! the cluster. When the local [`AppServer`](crate::AppServer) cannot find a
+//! `room_uuid` it consults the static [`ClusterMetadata`] allocation to locate
+//! the owning node and hands back a [`RemoteRoomChannel`] instead of a local
+//! `Addr<AppRoom>`: the channel forwards serialized [`RequestMessage`]s to the
+//! owning node over a [`RemoteNodeClient`] link and the node's
+//! [`ResponseMessage`]s are routed back to the originating session.
+//!
+//! A single [`RemoteNodeClient`] multiplexes every session that joins a room on
+//! the same owning node, so each inter-node frame carries a [`CorrelationId`]
+//! identifying the session it belongs to; the transport demultiplexes on that
+//! key and delivers a frame only to the matching session's sink. A
+//! [`Broadcasting`] component forwards room events to the remote nodes
+//! subscribed to a room.
+//!
+//! [`Cluster`] ties the pieces together (allocation, the live connection pool
+//! and the broadcast table) and is the handle the server bootstrap builds once
+//! at start-up and threads into every [`Session`](crate::websocket::session)
+//! via [`Session::with_cluster`](crate::websocket::session::Session::with_cluster).
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use actix::prelude::*;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::common::message::{RequestMessage, ResponseMessage};
+
+/// Network address of a cluster node, e.g. `ws://node-2.internal:8080/cluster`.
+pub type NodeAddress = String;
+
+/// Identifies the remote session a multiplexed inter-node frame belongs to.
+///
+/// Generated locally per [`RemoteRoomChannel`] and echoed by the owning node on
+/// every response so the transport can route it back to the one session that
+/// originated the exchange. [`BROADCAST_CORRELATION`] is reserved for room
+/// events sent by [`Broadcasting`].
+pub type CorrelationId = u64;
+
+/// Reserved [`CorrelationId`] for room-event fan-out by [`Broadcasting`]. No
+/// session sink is ever registered under it, so [`DemuxNodeLink::deliver`]
+/// drops a frame tagged with it like any other unknown correlation; the
+/// envelope carries no room uuid to route it by.
+pub const BROADCAST_CORRELATION: CorrelationId = 0;
+
+/// Sink invoked by a [`NodeLink`] with each inbound response frame addressed to
+/// a given session.
+pub type ResponseSink = Box<dyn Fn(Vec<u8>) + Send + Sync>;
+
+static NEXT_CORRELATION: AtomicU64 = AtomicU64::new(1);
+
+/// Allocate a process-unique [`CorrelationId`]. Starts at 1 so
+/// [`BROADCAST_CORRELATION`] stays reserved.
+fn next_correlation() -> CorrelationId {
+    NEXT_CORRELATION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Envelope wrapping every frame on the inter-node wire so a multiplexed link
+/// can route a response back to the session that sent the request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NodeEnvelope {
+    correlation: CorrelationId,
+    payload: Vec<u8>,
+}
+
+/// Half-open `[start, end)` range of the hashed-uuid space owned by a node.
+#[derive(Debug, Clone, Copy)]
+pub struct UuidRange {
+    pub start: u128,
+    pub end: u128,
+}
+
+impl UuidRange {
+    fn contains(&self, point: u128) -> bool {
+        self.start <= point && point < self.end
+    }
+}
+
+/// Static uuid-range-to-node allocation loaded from config at start-up.
+///
+/// Routing is deterministic: a room uuid is hashed into the `u128` key space and
+/// the node whose [`UuidRange`] contains that point owns the room.
+#[derive(Debug, Clone, Default)]
+pub struct ClusterMetadata {
+    allocation: Vec<(UuidRange, NodeAddress)>,
+}
+
+impl ClusterMetadata {
+    pub fn new(allocation: Vec<(UuidRange, NodeAddress)>) -> Self {
+        Self { allocation }
+    }
+
+    /// Resolve the node that owns `room_uuid`, or `None` when the uuid falls
+    /// outside every configured range.
+    pub fn find_node(&self, room_uuid: &str) -> Option<&NodeAddress> {
+        let point = hash_uuid(room_uuid);
+        self.allocation
+            .iter()
+            .find(|(range, _)| range.contains(point))
+            .map(|(_, node)| node)
+    }
+}
+
+/// Map a room uuid into the `u128` routing space with a stable FNV-1a hash.
+fn hash_uuid(room_uuid: &str) -> u128 {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    let mut hash = OFFSET;
+    for byte in room_uuid.as_bytes() {
+        hash ^= u128::from(*byte);
+        hash = hash.wrapping_mul(PRIME);
+    }
+    hash
+}
+
+/// Pool of live inter-node connections, keyed by node address, opened once at
+/// start-up and shared across all remote rooms a node owns.
+#[derive(Clone, Default)]
+pub struct RemoteNodeRegistry {
+    clients: HashMap<NodeAddress, RemoteNodeClient>,
+}
+
+impl RemoteNodeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, client: RemoteNodeClient) {
+        self.clients.insert(client.node_addr().clone(), client);
+    }
+
+    pub fn get(&self, node_addr: &str) -> Option<&RemoteNodeClient> {
+        self.clients.get(node_addr)
+    }
+}
+
+/// The inter-node link over which serialized frames are pushed to, and received
+/// from, a single owning node.
+///
+/// The production implementation is a persistent WebSocket connection opened by
+/// the cluster bootstrap; the trait is injected so the routing layer stays
+/// independent of the concrete transport (and testable with a fake sink).
+/// [`DemuxNodeLink`] is the concrete implementation used in the server.
+pub trait NodeLink: Send + Sync {
+    /// Push an already-serialized frame to the owning node.
+    fn send(&self, payload: Vec<u8>);
+
+    /// Register the sink that receives response frames addressed to
+    /// `correlation`. The transport invokes it only for inbound frames carrying
+    /// that key, so one multiplexed link fans responses to the right session.
+    fn subscribe(&self, correlation: CorrelationId, sink: ResponseSink);
+
+    /// Drop the sink for `correlation` so a gone session stops receiving frames
+    /// and its closure is released.
+    fn unsubscribe(&self, correlation: CorrelationId);
+}
+
+/// Concrete multiplexed [`NodeLink`]: writes outbound frames through an injected
+/// sender and demultiplexes inbound frames to the per-session sink keyed by the
+/// frame's [`CorrelationId`].
+///
+/// The bootstrap constructs one per peer, wiring `outbound` to the peer's socket
+/// writer and calling [`deliver`](Self::deliver) from the socket reader for each
+/// frame the peer pushes back.
+pub struct DemuxNodeLink {
+    outbound: ResponseSink,
+    sinks: Mutex<HashMap<CorrelationId, ResponseSink>>,
+}
+
+impl DemuxNodeLink {
+    pub fn new(outbound: impl Fn(Vec<u8>) + Send + Sync + 'static) -> Self {
+        Self {
+            outbound: Box::new(outbound),
+            sinks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Feed an inbound inter-node frame (as received from the peer socket) to
+    /// the session it is addressed to. Frames for an unknown correlation are
+    /// dropped with a warning rather than broadcast, preserving per-session
+    /// isolation. This includes frames tagged [`BROADCAST_CORRELATION`].
+    pub fn deliver(&self, frame: &[u8]) {
+        let envelope = match bincode::deserialize::<NodeEnvelope>(frame) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                warn!("Failed to decode inter-node frame: {}", err);
+                return;
+            }
+        };
+
+        let sinks = self.sinks.lock().unwrap();
+        match sinks.get(&envelope.correlation) {
+            Some(sink) => sink(envelope.payload),
+            None => warn!(
+                "Dropping inter-node frame for unknown correlation {}",
+                envelope.correlation
+            ),
+        }
+    }
+}
+
+impl NodeLink for DemuxNodeLink {
+    fn send(&self, payload: Vec<u8>) {
+        (self.outbound)(payload);
+    }
+
+    fn subscribe(&self, correlation: CorrelationId, sink: ResponseSink) {
+        self.sinks.lock().unwrap().insert(correlation, sink);
+    }
+
+    fn unsubscribe(&self, correlation: CorrelationId) {
+        self.sinks.lock().unwrap().remove(&correlation);
+    }
+}
+
+/// Thin client for the inter-node connection to a single owning node.
+///
+/// Shared (`Clone`) and held once per node in the [`RemoteNodeRegistry`]; every
+/// exchange is tagged with a [`CorrelationId`] so the one multiplexed
+/// [`NodeLink`] can route each response back to the session that owns it.
+#[derive(Clone)]
+pub struct RemoteNodeClient {
+    node_addr: NodeAddress,
+    link: Arc<dyn NodeLink>,
+}
+
+impl std::fmt::Debug for RemoteNodeClient {
+    // `NodeLink` is a bare `Send + Sync` transport with no `Debug` bound, so
+    // format only the addressable identity and elide the link handle.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteNodeClient")
+            .field("node_addr", &self.node_addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RemoteNodeClient {
+    pub fn connect(node_addr: NodeAddress, link: Arc<dyn NodeLink>) -> Self {
+        Self { node_addr, link }
+    }
+
+    pub fn node_addr(&self) -> &NodeAddress {
+        &self.node_addr
+    }
+
+    /// Forward a request to the owning node on behalf of `correlation`. The
+    /// request is serialized with the same wire format used for client frames,
+    /// wrapped in a [`NodeEnvelope`] so the node can address the response back,
+    /// and handed to the [`NodeLink`] for transmission.
+    fn forward(&self, correlation: CorrelationId, req: &RequestMessage) {
+        let payload = match bincode::serialize(req) {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!(
+                    "Failed to serialize request for node {}: {}",
+                    self.node_addr, err
+                );
+                return;
+            }
+        };
+
+        match bincode::serialize(&NodeEnvelope {
+            correlation,
+            payload,
+        }) {
+            Ok(frame) => {
+                debug!("Forwarding request to node {}", self.node_addr);
+                self.link.send(frame);
+            }
+            Err(err) => warn!(
+                "Failed to frame request for node {}: {}",
+                self.node_addr, err
+            ),
+        }
+    }
+
+    /// Register a session's inbound sink: decode each response frame addressed
+    /// to `correlation` and hand the [`ResponseMessage`] to `relay` so it
+    /// reaches that session only.
+    fn subscribe(
+        &self,
+        correlation: CorrelationId,
+        relay: impl Fn(ResponseMessage) + Send + Sync + 'static,
+    ) {
+        let node_addr = self.node_addr.clone();
+        self.link.subscribe(
+            correlation,
+            Box::new(move |payload| {
+                match bincode::deserialize::<ResponseMessage>(&payload) {
+                    Ok(msg) => relay(msg),
+                    Err(err) => warn!(
+                        "Failed to deserialize response from node {}: {}",
+                        node_addr, err
+                    ),
+                }
+            }),
+        );
+    }
+
+    /// Release a session's inbound sink once it disconnects.
+    fn unsubscribe(&self, correlation: CorrelationId) {
+        self.link.unsubscribe(correlation);
+    }
+}
+
+/// Handle to a room that lives on another node, scoped to one session.
+///
+/// [`forward`](Self::forward) pushes a client request to the owning node tagged
+/// with this channel's [`CorrelationId`]; the node's responses are decoded and
+/// delivered straight to the session via the sink installed in [`new`](Self::new).
+/// [`close`](Self::close) must be called when the session ends to release that
+/// sink, since the underlying [`RemoteNodeClient`] outlives the session.
+pub struct RemoteRoomChannel {
+    client: RemoteNodeClient,
+    correlation: CorrelationId,
+}
+
+impl RemoteRoomChannel {
+    pub fn new(client: RemoteNodeClient, session: Recipient<ResponseMessage>) -> Self {
+        let correlation = next_correlation();
+        // Install this session's sink under its own correlation key so only its
+        // frames reach it — two sessions on the same node never cross.
+        client.subscribe(correlation, move |msg| {
+            if let Err(err) = session.do_send(msg) {
+                warn!("Failed to relay remote response to session: {}", err);
+            }
+        });
+        Self {
+            client,
+            correlation,
+        }
+    }
+
+    pub fn forward(&self, req: &RequestMessage) {
+        self.client.forward(self.correlation, req);
+    }
+
+    /// Tear down the session's inbound subscription on the shared link. Without
+    /// this the sink would linger and keep pushing to a dead actor.
+    pub fn close(&self) {
+        self.client.unsubscribe(self.correlation);
+    }
+}
+
+/// Fans room events out to the remote nodes that have subscribed to a room.
+///
+/// Keyed by `room_uuid`, each entry holds the node clients that requested the
+/// room's event stream, letting a vote or reveal on the owning node reach every
+/// subscribing node in the cluster. Events are forwarded as serialized
+/// [`RequestMessage`]s tagged with [`BROADCAST_CORRELATION`]; a receiving
+/// [`DemuxNodeLink`] has no sink under that key and drops them, so they do not
+/// reach that node's sessions.
+#[derive(Default)]
+pub struct Broadcasting {
+    subscribers: HashMap<String, Vec<RemoteNodeClient>>,
+}
+
+impl Broadcasting {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribe a remote node to a room's event stream.
+    pub fn subscribe(&mut self, room_uuid: String, client: RemoteNodeClient) {
+        let subscribers = self.subscribers.entry(room_uuid).or_default();
+        if !subscribers
+            .iter()
+            .any(|existing| existing.node_addr() == client.node_addr())
+        {
+            subscribers.push(client);
+        }
+    }
+
+    /// Drop a node's subscriptions to a room.
+    pub fn unsubscribe(&mut self, room_uuid: &str, node_addr: &str) {
+        if let Some(subscribers) = self.subscribers.get_mut(room_uuid) {
+            subscribers.retain(|client| client.node_addr() != node_addr);
+        }
+    }
+
+    /// Mirror a room event to every subscribing node.
+    pub fn broadcast(&self, room_uuid: &str, req: &RequestMessage) {
+        if let Some(subscribers) = self.subscribers.get(room_uuid) {
+            for client in subscribers {
+                client.forward(BROADCAST_CORRELATION, req);
+            }
+        }
+    }
+}
+
+/// The cluster-wide federation state, built once at start-up and shared
+/// (behind an `Arc`) by every session.
+///
+/// It owns the static [`ClusterMetadata`] allocation, the live
+/// [`RemoteNodeRegistry`] connection pool, and the [`Broadcasting`] table. The
+/// join path uses [`find_remote_room`](Self::find_remote_room) to proxy a
+/// session to the owning node; the owning node's room handler uses
+/// [`subscribe_remote_node`](Self::subscribe_remote_node) and
+/// [`fan_out_event`](Self::fan_out_event) to stream that room's events to the
+/// other nodes holding its participants.
+pub struct Cluster {
+    metadata: ClusterMetadata,
+    nodes: RemoteNodeRegistry,
+    broadcasting: Mutex<Broadcasting>,
+}
+
+impl Cluster {
+    pub fn new(metadata: ClusterMetadata, nodes: RemoteNodeRegistry) -> Self {
+        Self {
+            metadata,
+            nodes,
+            broadcasting: Mutex::new(Broadcasting::new()),
+        }
+    }
+
+    /// `FindRoom` fallback: when the local [`AppServer`](crate::AppServer) has no
+    /// room for `room_uuid`, resolve the owning node from the allocation, look
+    /// up its live connection, and build a [`RemoteRoomChannel`] that proxies
+    /// this session to that node. Returns `None` when the uuid is unallocated or
+    /// the owning node has no open connection.
+    pub fn find_remote_room(
+        &self,
+        room_uuid: &str,
+        session: Recipient<ResponseMessage>,
+    ) -> Option<RemoteRoomChannel> {
+        let node_addr = self.metadata.find_node(room_uuid)?;
+        let client = self.nodes.get(node_addr)?;
+        Some(RemoteRoomChannel::new(client.clone(), session))
+    }
+
+    /// Owning-node hook: subscribe the node at `node_addr` to `room_uuid`'s
+    /// event stream so subsequent votes and reveals fan out to it.
+    pub fn subscribe_remote_node(&self, room_uuid: String, node_addr: &str) {
+        match self.nodes.get(node_addr) {
+            Some(client) => self
+                .broadcasting
+                .lock()
+                .unwrap()
+                .subscribe(room_uuid, client.clone()),
+            None => warn!("Cannot subscribe unknown node {} to room", node_addr),
+        }
+    }
+
+    /// Owning-node hook: drop a node's subscription to `room_uuid`.
+    pub fn unsubscribe_remote_node(&self, room_uuid: &str, node_addr: &str) {
+        self.broadcasting
+            .lock()
+            .unwrap()
+            .unsubscribe(room_uuid, node_addr);
+    }
+
+    /// Owning-node hook: fan a room event out to every node subscribed to
+    /// `room_uuid`.
+    pub fn fan_out_event(&self, room_uuid: &str, req: &RequestMessage) {
+        self.broadcasting.lock().unwrap().broadcast(room_uuid, req);
+    }
+}
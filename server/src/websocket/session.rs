@@ -1,4 +1,6 @@
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use actix_web_actors::ws;
@@ -10,16 +12,54 @@ use crate::client::store::DefaultClientStore;
 use crate::client::{ClientId, DEFAULT_CLIENT_ID};
 use crate::common::message::request::{CreateRoomParams, JoinRoomParams};
 use crate::common::message::{RequestMessage, ResponseMessage};
+use crate::poker::message::PresenceJoinMessage;
 use crate::poker::model::RoomModel;
 use crate::server::message::{
-    ConnectMessage as ConnectServerMessage, CreateRoomMessage as CreateRoomServerMessage,
+    BindTokenMessage as BindTokenServerMessage, ConnectMessage as ConnectServerMessage,
+    CreateRoomMessage as CreateRoomServerMessage, DisconnectMessage as DisconnectServerMessage,
     FindRoomMessage as FindRoomServerMessage,
 };
+use crate::server::federation::{Cluster, RemoteRoomChannel};
 use crate::user::info::{SharedUserInfo, UserInfo};
 use crate::user::model::UserORM;
 use crate::AppRoom;
 use crate::AppServer;
 
+/// Wire format used to (de)serialize messages for a session. `Json` keeps the
+/// human-readable text frames; `Bincode` switches to compact binary frames,
+/// which materially cuts bandwidth for the frequent vote/reveal broadcasts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    Bincode,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Json
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = ();
+
+    /// Parse the `?codec=` query param on the websocket upgrade request.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "bincode" => Ok(Codec::Bincode),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Default interval at which the server pings the client to verify the
+/// connection is alive; pass to [`Session::new`].
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Default time the server waits for any client frame before assuming the
+/// connection is half-open and dropping it; pass to [`Session::new`].
+pub const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
 type AppCreateRoomServerMessage = CreateRoomServerMessage<
     RoomModel,
     DefaultClientStore<DefaultClientChannel>,
@@ -39,7 +79,14 @@ where
     client_id: ClientId,
     server_addr: Addr<AppServer>,
     room_addr: Option<Addr<AppRoom>>,
+    remote_room: Option<RemoteRoomChannel>,
+    cluster: Option<Arc<Cluster>>,
     user_info: SharedUserInfo,
+    authenticated: bool,
+    codec: Codec,
+    heartbeat_interval: Duration,
+    client_timeout: Duration,
+    last_heartbeat: Instant,
 }
 
 impl<U> Actor for Session<U>
@@ -51,6 +98,8 @@ where
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("New websocket connection established");
 
+        self.start_heartbeat(ctx);
+
         let addr = ctx.address();
         self.server_addr
             .send(ConnectServerMessage {
@@ -67,6 +116,36 @@ where
             })
             .wait(ctx);
     }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        info!("Websocket client {} disconnecting", self.client_id);
+
+        let message = DisconnectServerMessage {
+            client_id: self.client_id,
+            room_addr: self.room_addr.clone(),
+        };
+        self.server_addr.do_send(message.clone());
+        if let Some(room_addr) = &self.room_addr {
+            // The room's `DisconnectMessage` handler removes the client and
+            // broadcasts the updated presence roster, so sending a separate
+            // `PresenceLeaveMessage` here would emit a second redundant roster
+            // update for one leave.
+            room_addr.do_send(message);
+        }
+
+        if let Some(remote_room) = &self.remote_room {
+            // Release this session's sink on the shared inter-node link; the
+            // link outlives the session, so dropping the channel alone would
+            // leave the sink pushing to a dead actor.
+            remote_room.close();
+        }
+
+        Running::Stop
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        info!("Websocket client {} disconnected", self.client_id);
+    }
 }
 
 impl<U> Handler<ResponseMessage> for Session<U>
@@ -76,7 +155,14 @@ where
     type Result = ();
 
     fn handle(&mut self, msg: ResponseMessage, ctx: &mut Self::Context) {
-        ctx.text(serde_json::to_string(&msg).expect("Error when serializing message"));
+        match self.codec {
+            Codec::Json => {
+                ctx.text(serde_json::to_string(&msg).expect("Error when serializing message"));
+            }
+            Codec::Bincode => {
+                ctx.binary(bincode::serialize(&msg).expect("Error when serializing message"));
+            }
+        }
     }
 }
 
@@ -91,7 +177,15 @@ where
         );
 
         match msg {
+            ws::Message::Ping(msg) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            ws::Message::Pong(_) => {
+                self.last_heartbeat = Instant::now();
+            }
             ws::Message::Text(msg_text) => {
+                self.last_heartbeat = Instant::now();
                 match serde_json::from_str::<RequestMessage>(&msg_text) {
                     Ok(req) => self.handle_request_message(req, ctx),
                     Err(_) => {
@@ -102,8 +196,21 @@ where
                     }
                 };
             }
-            ws::Message::Binary(_) => {
-                warn!("Unexpected Binary from websocket client {}", self.client_id)
+            ws::Message::Binary(bin) => {
+                self.last_heartbeat = Instant::now();
+                if self.codec != Codec::Bincode {
+                    warn!("Unexpected Binary from websocket client {}", self.client_id);
+                    return;
+                }
+                match bincode::deserialize::<RequestMessage>(&bin) {
+                    Ok(req) => self.handle_request_message(req, ctx),
+                    Err(_) => {
+                        warn!(
+                            "Unrecognized binary frame from websocket client {}",
+                            self.client_id
+                        );
+                    }
+                };
             }
             ws::Message::Close(_) => {
                 info!("Closing websocket client {}", self.client_id);
@@ -112,10 +219,6 @@ where
             ws::Message::Nop => (),
             _ => (),
         }
-
-        // TODO: Removed debug message
-        ctx.address()
-            .do_send(ResponseMessage::RoomClosed(String::from("Haha")));
     }
 }
 
@@ -123,25 +226,157 @@ impl<U> Session<U>
 where
     U: UserORM,
 {
-    pub fn new(server_addr: Addr<AppServer>, user_model: U, user_info: UserInfo) -> Self {
+    pub fn new(
+        server_addr: Addr<AppServer>,
+        user_model: U,
+        user_info: UserInfo,
+        heartbeat_interval: Duration,
+        client_timeout: Duration,
+    ) -> Self {
         Self {
             user_model,
             client_id: DEFAULT_CLIENT_ID,
             server_addr,
             room_addr: None,
+            remote_room: None,
+            cluster: None,
             user_info: SharedUserInfo::new(user_info),
+            authenticated: false,
+            codec: Codec::default(),
+            heartbeat_interval,
+            client_timeout,
+            last_heartbeat: Instant::now(),
         }
     }
 
-    fn handle_request_message(&self, req: RequestMessage, ctx: &mut ws::WebsocketContext<Self>) {
+    /// Pre-select the wire format at connect time. The upgrade route parses the
+    /// `?codec=` query param (see [`Codec::from_str`]) and threads the result
+    /// in here; this is the sole negotiation path, keeping [`Codec`] in the
+    /// websocket layer rather than leaking it into `common::message`.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Attach the shared [`Cluster`] state so a `JoinRoom` can fall back to a
+    /// room owned by another node. The upgrade route threads the cluster handle
+    /// built at start-up in here; without it the session stays single-node.
+    pub fn with_cluster(mut self, cluster: Arc<Cluster>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Spawn the periodic heartbeat check. Every `heartbeat_interval` the server
+    /// verifies that a client frame has been seen within `client_timeout`; if
+    /// not the connection is assumed dead and the actor is stopped, otherwise a
+    /// ping is emitted.
+    fn start_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let client_timeout = self.client_timeout;
+        ctx.run_interval(self.heartbeat_interval, move |actor, ctx| {
+            if Instant::now().duration_since(actor.last_heartbeat) > client_timeout {
+                warn!(
+                    "Websocket client {} heartbeat timed out, disconnecting",
+                    actor.client_id
+                );
+                // `stop` drives the `stopping` hook, which already notifies the
+                // server and the room of the disconnect, so don't `do_send` a
+                // second `DisconnectMessage` here or the server sees two
+                // disconnects for one client.
+                ctx.stop();
+                return;
+            }
+
+            ctx.ping(b"");
+        });
+    }
+
+    fn handle_request_message(&mut self, req: RequestMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        // The auth handshake is the only exchange allowed before the session has
+        // resolved a token to a real user. Every other request is rejected until
+        // then, and a session that loses its authentication must re-authenticate.
         match req {
+            RequestMessage::Authenticate { token } => self.handle_authenticate(token, ctx),
+            RequestMessage::BindToken { token } => self.handle_bind_token(token, ctx),
+            _ if !self.authenticated => {
+                warn!(
+                    "Rejecting request from unauthenticated websocket client {}",
+                    self.client_id
+                );
+                ctx.address().do_send(ResponseMessage::Unauthorized);
+            }
             RequestMessage::CreateRoom(message) => self.handle_create_room(message, ctx),
             RequestMessage::JoinRoom(message) => self.handle_join(message, ctx),
-            // TODO:
-            _ => unreachable!(),
+            // Any other authenticated request (vote, reveal, leave) has no
+            // handler in the session and is neither sent to the joined room
+            // nor forwarded to a remote one. Log and ignore it rather than
+            // panicking the actor on otherwise valid input.
+            other => {
+                debug!(
+                    "Ignoring unhandled request {:?} from websocket client {}",
+                    other, self.client_id
+                );
+            }
+        }
+    }
+
+    /// Resolve an auth token to a real [`UserInfo`] through the [`UserORM`].
+    /// On success the placeholder user info is replaced and the session is
+    /// promoted to the authenticated state; on failure the client is told its
+    /// authentication is expired.
+    fn handle_authenticate(&mut self, token: String, ctx: &mut ws::WebsocketContext<Self>) {
+        info!(
+            "Authenticating websocket client {} from token",
+            self.client_id
+        );
+
+        match self.user_model.find_user_by_token(&token) {
+            Some(user_info) => {
+                self.user_info = SharedUserInfo::new(user_info);
+                self.authenticated = true;
+            }
+            None => {
+                warn!(
+                    "Authentication failed for websocket client {}",
+                    self.client_id
+                );
+                self.authenticated = false;
+                ctx.address().do_send(ResponseMessage::AuthExpired);
+            }
         }
     }
 
+    /// Exchange a freshly issued token for this live session so a reconnecting
+    /// client resumes with its previous state. The token is first resolved like
+    /// a normal authentication; once authenticated the server is asked to hand
+    /// back the room the user was last in (if any) so the session rejoins it
+    /// without a fresh `JoinRoom`, re-announcing presence to the room members.
+    fn handle_bind_token(&mut self, token: String, ctx: &mut ws::WebsocketContext<Self>) {
+        info!("Binding token to websocket client {}", self.client_id);
+
+        self.handle_authenticate(token, ctx);
+        if !self.authenticated {
+            return;
+        }
+
+        self.server_addr
+            .send(BindTokenServerMessage {
+                client_id: self.client_id,
+                user_info: self.user_info.clone(),
+            })
+            .into_actor(self)
+            .then(|handler_result, actor, _ctx| {
+                if let Ok(Some(room_addr)) = handler_result {
+                    room_addr.do_send(PresenceJoinMessage {
+                        client_id: actor.client_id,
+                        user_info: actor.user_info.clone(),
+                    });
+                    actor.room_addr = Some(room_addr);
+                }
+                fut::ok(())
+            })
+            .wait(ctx);
+    }
+
     fn handle_create_room(&self, params: CreateRoomParams, ctx: &mut ws::WebsocketContext<Self>) {
         info!(
             "Receiver create room request from websocket client {}",
@@ -162,7 +397,13 @@ where
             .then(|handler_result, actor, ctx| {
                 match handler_result {
                     Ok(room_addr_result) => match room_addr_result {
-                        Ok(room_addr) => actor.room_addr = Some(room_addr),
+                        Ok(room_addr) => {
+                            room_addr.do_send(PresenceJoinMessage {
+                                client_id: actor.client_id,
+                                user_info: actor.user_info.clone(),
+                            });
+                            actor.room_addr = Some(room_addr);
+                        }
                         _ => ctx.stop(),
                     },
                     _ => ctx.stop(),
@@ -180,6 +421,10 @@ where
 
         // TODO: Check if user already in a room
 
+        // Keep the params so a not-found-locally result can fall back to the
+        // node that owns the room (see `try_join_remote`).
+        let join_params = params.clone();
+
         self.server_addr
             .send(AppFindRoomServerMessage {
                 client_id: self.client_id,
@@ -189,20 +434,56 @@ where
                 client_channel_type: PhantomData,
             })
             .into_actor(self)
-            .then(|handler_result, actor, ctx| {
+            .then(move |handler_result, actor, ctx| {
                 match handler_result {
-                    Ok(room_addr_result) => match room_addr_result {
-                        Ok(room_addr) => {
-                            // room_addr.do_send(message);
+                    Ok(Ok(room_addr)) => {
+                        room_addr.do_send(PresenceJoinMessage {
+                            client_id: actor.client_id,
+                            user_info: actor.user_info.clone(),
+                        });
 
-                            actor.room_addr = Some(room_addr);
+                        actor.room_addr = Some(room_addr);
+                    }
+                    // The local `AppServer` has no such room: fall back to the
+                    // owning node before giving up on the join.
+                    _ => {
+                        if !actor.try_join_remote(join_params, ctx) {
+                            ctx.stop();
                         }
-                        _ => ctx.stop(),
-                    },
-                    _ => ctx.stop(),
+                    }
                 };
                 fut::ok(())
             })
             .wait(ctx);
     }
+
+    /// `FindRoom` fallback: resolve `room_uuid` to the node that owns it through
+    /// the cluster allocation and, when one is found, open a
+    /// [`RemoteRoomChannel`] that forwards this session's `JoinRoom` to that
+    /// node and relays its responses back. Returns `false` when the session has
+    /// no cluster tables, the uuid is unallocated, or the owning node has no
+    /// open connection, so the caller can stop the actor as before.
+    fn try_join_remote(
+        &mut self,
+        params: JoinRoomParams,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> bool {
+        let cluster = match &self.cluster {
+            Some(cluster) => cluster,
+            None => return false,
+        };
+
+        match cluster.find_remote_room(&params.room_uuid, ctx.address().recipient()) {
+            Some(channel) => {
+                info!(
+                    "Joining websocket client {} to remote room {}",
+                    self.client_id, params.room_uuid
+                );
+                channel.forward(&RequestMessage::JoinRoom(params));
+                self.remote_room = Some(channel);
+                true
+            }
+            None => false,
+        }
+    }
 }